@@ -1,45 +1,54 @@
-use chashmap::CHashMap;
+use std::alloc::{AllocError, Global};
 use std::hash::Hash;
-use std::sync::{Arc, Weak};
 
+use crate::backing::HashBacking;
 use crate::{Allocator, Id};
 
-/// An allocator based on a concurrent hashmap
-pub struct HashAllocator<T> {
-    backing: CHashMap<Arc<T>, Weak<T>>,
+/// An allocator based on a sharded, concurrent hash table. See `backing::HashBacking`.
+///
+/// `A` is the storage allocator payloads are allocated with, defaulting to the global heap.
+pub struct HashAllocator<T, A: std::alloc::Allocator = Global> {
+    backing: HashBacking<T, A>,
 }
 
-impl<T> Default for HashAllocator<T> {
+impl<T, A: std::alloc::Allocator + Clone + Default> Default for HashAllocator<T, A> {
     fn default() -> Self {
-        let backing = CHashMap::new();
-        Self { backing }
+        Self {
+            backing: HashBacking::new_in(0, A::default()),
+        }
     }
 }
 
-impl<T: Eq + Hash> Allocator<T> for HashAllocator<T> {
-    fn allocate(&self, t: T) -> Id<T> {
-        let key = Arc::new(t);
-        let value = Arc::downgrade(&key);
-        let mut result = Arc::clone(&key);
-
-        self.backing.upsert(
-            key,
-            || value,
-            |other| {
-                result = Weak::upgrade(other).unwrap();
-            },
-        );
-        Id(result)
+impl<T: Eq + Hash, A: std::alloc::Allocator + Clone + Default> Allocator<T> for HashAllocator<T, A> {
+    type Storage = A;
+
+    fn try_allocate(&self, t: T) -> Result<Id<T, A>, AllocError> {
+        self.backing.try_unique(t)
     }
 
     fn allocations(&self) -> usize {
-        self.backing.len()
+        self.backing.num_entries()
     }
 
     fn delete_unused(&self) {
-        // OK since each bucket is locked first
-        self.backing
-            .retain(|key, _value| Arc::strong_count(key) > 1);
-        self.backing.shrink_to_fit();
+        self.backing.delete_unused()
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<T: Eq + Hash + Send + Sync, A: std::alloc::Allocator + Clone + Default + Send + Sync>
+    HashAllocator<T, A>
+{
+    /// Like `delete_unused`, but sweeps across a thread pool. See `HashBacking::par_delete_unused`.
+    pub fn par_delete_unused(&self) {
+        self.backing.par_delete_unused()
+    }
+
+    /// Intern a batch of values in parallel. See `HashBacking::par_allocate_many`.
+    pub fn par_allocate_many<I>(&self, iter: I) -> Vec<Id<T, A>>
+    where
+        I: rayon::iter::IntoParallelIterator<Item = T>,
+    {
+        self.backing.par_allocate_many(iter)
     }
 }
@@ -1,85 +1,198 @@
-use chashmap::CHashMap;
+use std::alloc::{AllocError, Global};
+use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::mem::drop;
-use std::ptr::NonNull;
+use std::sync::{Arc, RwLock};
+
+use hashbrown::raw::RawTable;
 
 use crate::Id;
 
-struct Key<T>(*const T);
+fn hash_of<T: Hash + ?Sized>(val: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    val.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// One shard of a `HashBacking`: a table guarded by its own lock, so that interning into
+/// different shards never contends.
+///
+/// The table's own bookkeeping memory is always allocated with `allocator_api2::alloc::Global`
+/// (hashbrown's stable, non-`std`-`Allocator` default) regardless of `A` - only the `Id<T, A>`
+/// payloads stored in it use the caller's storage allocator. This keeps hashbrown itself on its
+/// default, stable build (no need for its nightly-only `"nightly"` feature) even though `A` is
+/// the real, unstable `std::alloc::Allocator`.
+struct Shard<T, A: std::alloc::Allocator> {
+    table: RwLock<RawTable<Id<T, A>, allocator_api2::alloc::Global>>,
+    alloc: A,
+    /// Must match the `shard_bits` of the owning `HashBacking`: every hash stored in or looked
+    /// up from `table` has already had its top `shard_bits` bits (used to pick this shard)
+    /// rotated down, and any hasher handed to `table` must reproduce that same rotation or
+    /// resizing will file entries under the wrong hash and they become unfindable.
+    shard_bits: u32,
+}
 
-impl<T> Clone for Key<T> {
-    fn clone(&self) -> Self {
-        Key(self.0)
+impl<T, A: std::alloc::Allocator> Shard<T, A> {
+    fn with_capacity_in(capacity: usize, alloc: A, shard_bits: u32) -> Self {
+        Shard {
+            table: RwLock::new(RawTable::with_capacity_in(
+                capacity,
+                allocator_api2::alloc::Global,
+            )),
+            alloc,
+            shard_bits,
+        }
     }
 }
 
-impl<T> Copy for Key<T> {}
-unsafe impl<T> Sync for Key<T> {}
-unsafe impl<T> Send for Key<T> {}
+impl<T: Hash, A: std::alloc::Allocator> Shard<T, A> {
+    fn hasher(&self) -> impl Fn(&Id<T, A>) -> u64 + '_ {
+        move |existing: &Id<T, A>| hash_of(existing.as_ref()).rotate_left(self.shard_bits)
+    }
 
-impl<T: PartialEq> PartialEq for Key<T> {
-    fn eq(&self, other: &Self) -> bool {
-        unsafe { (*self.0).eq(&*other.0) }
+    /// Reclaim entries whose only remaining reference is the one held by this shard itself.
+    /// Independent of every other shard, so may safely run concurrently with them.
+    fn sweep(&self) {
+        let mut table = self.table.write().unwrap();
+        let to_erase: Vec<_> = unsafe { table.iter() }
+            .filter(|bucket| unsafe { Id::strong_count(bucket.as_ref()) == 1 })
+            .collect();
+        for bucket in to_erase {
+            unsafe {
+                table.erase(bucket);
+            }
+        }
+        let len = table.len();
+        table.shrink_to(len, self.hasher());
     }
 }
 
-impl<T: Hash> Hash for Key<T> {
-    fn hash<H: Hasher>(&self, hasher: &mut H) {
-        unsafe {
-            (*self.0).hash(hasher);
-        }
+/// A backing store based on a sharded, concurrent hash table.
+///
+/// Values are routed to one of a power-of-two number of shards by the top bits of their hash,
+/// and the remaining bits are used as the in-table hash. Interning a value that is already
+/// present - the overwhelmingly common case - therefore only ever takes a read lock on a single
+/// shard; only an actual miss needs to upgrade to that shard's write lock.
+///
+/// `A` is the storage allocator payloads are allocated with, defaulting to the global heap; the
+/// tables' own bookkeeping memory always comes from the global heap (see `Shard`). A bump or arena allocator lets a whole generation of interned terms be
+/// reclaimed at once by dropping `A`, rather than freeing each `Id` individually.
+pub struct HashBacking<T, A: std::alloc::Allocator = Global> {
+    shards: Box<[Shard<T, A>]>,
+    shard_bits: u32,
+}
+
+impl<T> HashBacking<T, Global> {
+    /// Create a new backing store, pre-allocating (approximately) `capacity` items spread
+    /// evenly across shards.
+    pub fn new(capacity: usize) -> Self {
+        Self::new_in(capacity, Global)
     }
 }
 
-/// A backing store based on a concurrent hashmap.
-pub struct HashBacking<T> {
-    backing: CHashMap<Key<T>, Id<T>>,
+impl<T, A: std::alloc::Allocator + Clone> HashBacking<T, A> {
+    /// Like `new`, but allocates payloads with `alloc` instead of the global heap. The tables
+    /// themselves still use the global heap.
+    pub fn new_in(capacity: usize, alloc: A) -> Self {
+        let nshards = num_cpus::get().next_power_of_two();
+        let per_shard = capacity / nshards;
+        let shard_bits = nshards.trailing_zeros();
+        let shards = (0..nshards)
+            .map(|_| Shard::with_capacity_in(per_shard, alloc.clone(), shard_bits))
+            .collect();
+        HashBacking { shards, shard_bits }
+    }
 }
 
-impl<T> HashBacking<T> {
+impl<T, A: std::alloc::Allocator> HashBacking<T, A> {
     /// How many items are currently stored?
     pub fn num_entries(&self) -> usize {
-        self.backing.len()
+        self.shards
+            .iter()
+            .map(|shard| shard.table.read().unwrap().len())
+            .sum()
     }
-}
 
-impl<T> HashBacking<T> {
-    /// Create a new backing store, pre-allocating `capacity` items.
-    pub fn new(capacity: usize) -> Self {
-        HashBacking {
-            backing: CHashMap::with_capacity(capacity),
-        }
+    fn shard_for(&self, hash: u64) -> (&Shard<T, A>, u64) {
+        let index = if self.shard_bits == 0 {
+            0
+        } else {
+            (hash >> (64 - self.shard_bits)) as usize
+        };
+        (&self.shards[index], hash.rotate_left(self.shard_bits))
     }
 }
 
-impl<T: PartialEq + Hash> HashBacking<T> {
+impl<T: PartialEq + Hash, A: std::alloc::Allocator + Clone> HashBacking<T, A> {
     /// Allows implementing `Backed` for any type that implements `Eq + Hash`.
-    pub fn unique(&self, val: T) -> Id<T> {
-        let key = Key(&val);
-        if let Some(id) = self.backing.get(&key) {
-            return *id;
+    ///
+    /// Panics if allocation fails. See `try_unique` for a fallible version.
+    pub fn unique(&self, val: T) -> Id<T, A> {
+        self.try_unique(val).expect("allocation failed")
+    }
+
+    /// Like `unique`, but returns an error rather than panicking if allocation fails
+    pub fn try_unique(&self, val: T) -> Result<Id<T, A>, AllocError> {
+        let (shard, hash) = self.shard_for(hash_of(&val));
+
+        // fast path: single probe under a read lock, no allocation if already interned
+        {
+            let table = shard.table.read().unwrap();
+            if let Some(id) = table.get(hash, |existing| existing.as_ref() == &val) {
+                return Ok(id.clone());
+            }
+        }
+
+        let boxed = Arc::try_new_in(val, shard.alloc.clone())?;
+        let mut table = shard.table.write().unwrap();
+        table.try_reserve(1, shard.hasher()).map_err(|_| AllocError)?;
+
+        let bucket = match table.find_or_find_insert_slot(
+            hash,
+            |existing: &Id<T, A>| existing.as_ref() == boxed.as_ref(),
+            shard.hasher(),
+        ) {
+            // lost the race to another thread: `boxed` is dropped, freeing it
+            Ok(bucket) => bucket,
+            Err(slot) => unsafe { table.insert_in_slot(hash, slot, Id(boxed)) },
+        };
+        Ok(unsafe { bucket.as_ref() }.clone())
+    }
+
+    /// Sweep for unused values and delete them
+    pub fn delete_unused(&self) {
+        for shard in self.shards.iter() {
+            shard.sweep();
         }
+    }
+}
+
+#[cfg(feature = "rayon")]
+mod rayon_support {
+    use rayon::prelude::*;
+    use std::hash::Hash;
 
-        let boxed = Box::new(val);
-        let pointer = Box::into_raw(boxed);
-        let key = Key(pointer);
-        let id = Id(unsafe { NonNull::new_unchecked(pointer) });
-        let mut insert_failed = false;
-
-        self.backing.upsert(
-            key,
-            || id,
-            |_| {
-                insert_failed = true;
-            },
-        );
-
-        let result = *self.backing.get(&key).unwrap();
-        if insert_failed {
-            let reboxed = unsafe { Box::from_raw(pointer) };
-            drop(reboxed);
+    use super::HashBacking;
+    use crate::Id;
+
+    impl<T: Hash + Send + Sync, A: std::alloc::Allocator + Send + Sync> HashBacking<T, A> {
+        /// Like `delete_unused`, but sweeps shards across a thread pool instead of one at a
+        /// time, since each shard is reclaimed independently of the others.
+        pub fn par_delete_unused(&self) {
+            self.shards.par_iter().for_each(|shard| shard.sweep());
         }
+    }
 
-        result
+    impl<T: PartialEq + Hash + Send + Sync, A: std::alloc::Allocator + Clone + Send + Sync>
+        HashBacking<T, A>
+    {
+        /// Intern a batch of values in parallel, returning their `Id`s in input order.
+        ///
+        /// Panics if allocation fails.
+        pub fn par_allocate_many<I>(&self, iter: I) -> Vec<Id<T, A>>
+        where
+            I: IntoParallelIterator<Item = T>,
+        {
+            iter.into_par_iter().map(|t| self.unique(t)).collect()
+        }
     }
 }
@@ -1,3 +1,5 @@
+#![feature(allocator_api)]
+
 //! Allocators which create one unique, shared pointer per distinct object.
 //! Useful for applications with highly-redundant data structures such as compilers or automatic theorem provers.
 //!
@@ -7,47 +9,52 @@
 //! Occasionally you may wish to "garbage collect" unused objects.
 //! This can be achieved with `Allocator::delete_unused`.
 //!
+//! This crate requires a nightly compiler (see `rust-toolchain.toml`): fallible allocation
+//! (`try_new`/`try_allocate`) is built on the unstable `Arc::try_new_in`, and custom storage
+//! allocators are built on the unstable `std::alloc::Allocator` trait.
+//!
 //! # Example
 //! ```rust
-//! use unique::{Allocated, Id, make_allocator};
+//! #![feature(allocator_api)]
+//! use unique::{Allocated, Allocator, Id, make_allocator};
 //! use unique::allocators::HashAllocator;
 //!
-//! #[derive(PartialEq, Eq, Hash)]
+//! #[derive(Debug, PartialEq, Eq, Hash)]
 //! enum Expr {
 //!     Const(i32),
 //!     Add(Id<Expr>, Id<Expr>),
 //! }
 //! make_allocator!(Expr, EXPR_ALLOC, HashAllocator);
 //!
-//! #[test]
-//! fn example() {
-//!     use Expr::*;
+//! use Expr::*;
 //!
-//!     // Equivalent ways of allocating a `2` object.
-//!     let two_x = Expr::allocator().allocate(Const(2));
-//!     let two_y = EXPR_ALLOC.allocate(Const(2));
-//!     let two_z = Id::new(Const(2));
-//!     assert_eq!(*two_x, *two_y, *two_z, Const(2));
-//!     assert_eq!(two_x, two_y, two_z);
+//! // Equivalent ways of allocating a `2` object.
+//! let two_x = Expr::allocator().allocate(Const(2));
+//! let two_y = EXPR_ALLOC.allocate(Const(2));
+//! let two_z = Id::new(Const(2));
+//! assert_eq!(*two_x, Const(2));
+//! assert_eq!(two_x, two_y);
+//! assert_eq!(two_y, two_z);
 //!
-//!     // A distinct object, 2 + 2.
-//!     let four = Id::new(Add(two_x.clone(), two_y.clone()));
-//!     assert_ne!(two_x, four);
+//! // A distinct object, 2 + 2.
+//! let four = Id::new(Add(two_x.clone(), two_y.clone()));
+//! assert_ne!(two_x, four);
 //!
-//!     // Note only two allocations.
-//!     assert_eq!(EXPR_ALLOC.allocations(), 2);
+//! // Note only two allocations.
+//! assert_eq!(EXPR_ALLOC.allocations(), 2);
 //!
-//!     std::mem::drop(four);
+//! std::mem::drop(four);
 //!
-//!     // Still two allocations.
-//!     assert_eq!(EXPR_ALLOC.allocations(), 2);
-//!     EXPR_ALLOC.delete_unused();
-//!     // Now four is no more.
-//!     assert_eq!(EXPR_ALLOC.allocations(), 1);
-//! }
+//! // Still two allocations.
+//! assert_eq!(EXPR_ALLOC.allocations(), 2);
+//! EXPR_ALLOC.delete_unused();
+//! // Now four is no more.
+//! assert_eq!(EXPR_ALLOC.allocations(), 1);
 //! ```
 
+use std::alloc::{AllocError, Global};
 use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::ops::Deref;
@@ -56,13 +63,29 @@ use std::sync::Arc;
 /// Possible allocators to use
 pub mod allocators;
 
+/// Low-level backing stores that allocators are built on
+pub mod backing;
+
+#[cfg(feature = "serde")]
+mod serde_impl;
+
 #[cfg(test)]
 mod tests;
 
 /// Allocate shared unique pointers
 pub trait Allocator<T: Eq>: Default {
-    /// Recycle a value if possible, or allocate a new one
-    fn allocate(&self, t: T) -> Id<T>;
+    /// The storage allocator that payloads are allocated with. See `Id`'s second type parameter.
+    type Storage: std::alloc::Allocator;
+
+    /// Recycle a value if possible, or allocate a new one.
+    ///
+    /// Panics if allocation fails. See `try_allocate` for a fallible version.
+    fn allocate(&self, t: T) -> Id<T, Self::Storage> {
+        self.try_allocate(t).expect("allocation failed")
+    }
+
+    /// Like `allocate`, but returns an error rather than panicking if allocation fails
+    fn try_allocate(&self, t: T) -> Result<Id<T, Self::Storage>, AllocError>;
 
     /// The current number of allocations
     fn allocations(&self) -> usize;
@@ -80,25 +103,42 @@ pub trait Allocated: Eq + Sized + 'static {
     fn allocator() -> &'static Self::Alloc;
 }
 
-/// A unique, shared pointer
+/// A unique, shared pointer.
 ///
-#[derive(Default, PartialOrd, Ord)]
-pub struct Id<T>(Arc<T>);
+/// `A` is the underlying storage allocator payloads are allocated with, defaulting to the
+/// global heap. This lets a backing store that was built with a custom `A` (e.g. a bump
+/// allocator for a short-lived interning pass) hand out `Id`s backed by that same storage.
+pub struct Id<T, A: std::alloc::Allocator = Global>(Arc<T, A>);
 
-impl<T> Id<T> {
+impl<T, A: std::alloc::Allocator> Id<T, A> {
     /// Produce a unique integral identifier from an `Id`
     pub fn id(p: &Self) -> usize {
         &*p.0 as *const T as usize
     }
 
+    /// The number of `Id`s (including any held by a backing store itself) sharing this pointer
+    pub(crate) fn strong_count(p: &Self) -> usize {
+        Arc::strong_count(&p.0)
+    }
+}
+
+impl<T> Id<T> {
     /// Consumes the `Id` and produces a raw pointer.
     /// Must be converted back with `from_raw` to avoid a leak.
+    ///
+    /// # Safety
+    /// The returned pointer must be converted back to an `Id` with `from_id` exactly once, and
+    /// not otherwise dereferenced or freed.
     #[allow(clippy::wrong_self_convention)]
     pub unsafe fn into_raw(p: Self) -> *const T {
         Arc::into_raw(p.0)
     }
 
     /// Must have previously been produced by `Id::into_raw`.
+    ///
+    /// # Safety
+    /// `id` must be a pointer previously returned by `Id::into_raw`, and must not have already
+    /// been converted back with `from_id`.
     pub unsafe fn from_id(id: usize) -> Id<T> {
         let ptr = id as *const T;
         let arc = Arc::from_raw(ptr);
@@ -106,34 +146,62 @@ impl<T> Id<T> {
     }
 }
 
-impl<T: Allocated> Id<T> {
-    /// Get a shared pointer to (something value-equal to) `t`
+impl<T: Allocated> Id<T, <T::Alloc as Allocator<T>>::Storage> {
+    /// Get a shared pointer to (something value-equal to) `t`.
+    ///
+    /// Panics if allocation fails. See `try_new` for a fallible version.
     pub fn new(t: T) -> Self {
         T::allocator().allocate(t)
     }
+
+    /// Like `new`, but returns an error rather than panicking if allocation fails
+    pub fn try_new(t: T) -> Result<Self, AllocError> {
+        T::allocator().try_allocate(t)
+    }
 }
 
-impl<T> Clone for Id<T> {
+impl<T, A: std::alloc::Allocator + Default> Default for Id<T, A>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Id(Arc::new_in(T::default(), A::default()))
+    }
+}
+
+impl<T, A: std::alloc::Allocator + Clone> Clone for Id<T, A> {
     fn clone(&self) -> Self {
         Id(Arc::clone(&self.0))
     }
 }
 
-impl<T> PartialEq for Id<T> {
+impl<T, A: std::alloc::Allocator> PartialEq for Id<T, A> {
     fn eq(&self, other: &Self) -> bool {
         Arc::ptr_eq(&self.0, &other.0)
     }
 }
 
-impl<T> Eq for Id<T> {}
+impl<T, A: std::alloc::Allocator> Eq for Id<T, A> {}
 
-impl<T> Hash for Id<T> {
+impl<T: PartialOrd, A: std::alloc::Allocator> PartialOrd for Id<T, A> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.0.partial_cmp(&other.0)
+    }
+}
+
+impl<T: Ord, A: std::alloc::Allocator> Ord for Id<T, A> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl<T, A: std::alloc::Allocator> Hash for Id<T, A> {
     fn hash<H: Hasher>(&self, hasher: &mut H) {
         Id::id(self).hash(hasher);
     }
 }
 
-impl<T> Deref for Id<T> {
+impl<T, A: std::alloc::Allocator> Deref for Id<T, A> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -141,51 +209,57 @@ impl<T> Deref for Id<T> {
     }
 }
 
-impl<T: fmt::Debug> fmt::Debug for Id<T> {
+impl<T: fmt::Debug, A: std::alloc::Allocator> fmt::Debug for Id<T, A> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
     }
 }
 
-impl<T: fmt::Display> fmt::Display for Id<T> {
+impl<T: fmt::Display, A: std::alloc::Allocator> fmt::Display for Id<T, A> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
     }
 }
 
-impl<T> fmt::Pointer for Id<T> {
+impl<T, A: std::alloc::Allocator> fmt::Pointer for Id<T, A> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
     }
 }
 
-impl<T> AsRef<T> for Id<T> {
+impl<T, A: std::alloc::Allocator> AsRef<T> for Id<T, A> {
     fn as_ref(&self) -> &T {
         self.0.as_ref()
     }
 }
 
-impl<T> Borrow<T> for Id<T> {
+impl<T, A: std::alloc::Allocator> Borrow<T> for Id<T, A> {
     fn borrow(&self) -> &T {
         self.0.borrow()
     }
 }
 
-/// `make_allocator!(Type, NAME, Allocator)`
+/// `make_allocator!(Type, NAME, Allocator)` or `make_allocator!(Type, NAME, Allocator, Storage)`
 ///
 /// Performs the following steps:
 /// - Create a static reference to an `Allocator<Type>` accessible by `NAME`.
 /// - Lazily initialise (via `lazy_static`) to `Allocator::default()`.
 /// - Implements `Allocated` for `Type` by using this allocator.
+///
+/// An optional fourth argument picks the storage allocator (see `Id`'s second type parameter)
+/// that payloads are allocated with, defaulting to the global heap.
 #[macro_export]
 macro_rules! make_allocator {
     ($type:ty, $name:ident, $alloc:ident) => {
+        $crate::make_allocator!($type, $name, $alloc, ::std::alloc::Global);
+    };
+    ($type:ty, $name:ident, $alloc:ident, $storage:ty) => {
         lazy_static::lazy_static! {
-            static ref $name: $alloc<$type> = $alloc::default();
+            static ref $name: $alloc<$type, $storage> = $alloc::default();
         }
 
         impl $crate::Allocated for $type {
-            type Alloc = $alloc<$type>;
+            type Alloc = $alloc<$type, $storage>;
             fn allocator() -> &'static Self::Alloc {
                 &$name
             }
@@ -0,0 +1,26 @@
+//! `serde` support for `Id<T>`, gated behind the `serde` feature.
+//!
+//! `Id<T>` serializes as whatever `T` serializes as: the pointer identity of an `Id` is not
+//! stable across runs, so only the pointee is written out. Deserializing goes back through the
+//! allocator, so round-tripping is value-preserving but not pointer-preserving - a freshly
+//! deserialized value simply gets interned as usual, and two equal values deserialized
+//! separately (even from separate documents) end up pointer-equal to each other and to any
+//! matching value already live in the process.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Allocated, Allocator, Id};
+
+impl<T: Serialize, A: std::alloc::Allocator> Serialize for Id<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        T::serialize(self, serializer)
+    }
+}
+
+impl<'de, T: Allocated + Deserialize<'de>> Deserialize<'de>
+    for Id<T, <T::Alloc as Allocator<T>>::Storage>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Id::new)
+    }
+}
@@ -43,3 +43,149 @@ fn example() {
     Expr::allocator().delete_unused();
     assert_eq!(Expr::allocator().allocations(), 2);
 }
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug)]
+enum Fallible {
+    Leaf(i32),
+}
+make_allocator!(Fallible, __FALLIBLE_ALLOC, HashAllocator);
+
+#[test]
+fn try_new_dedups_after_a_miss() {
+    use Fallible::Leaf;
+
+    let a = Id::try_new(Leaf(1)).unwrap();
+    let b = Id::try_new(Leaf(1)).unwrap();
+    assert_eq!(a, b);
+    assert_eq!(a.as_ref() as *const Fallible, b.as_ref() as *const Fallible);
+}
+
+#[test]
+fn concurrent_try_new_of_equal_values_collapses_to_one_id() {
+    use std::thread;
+    use Fallible::Leaf;
+
+    // Several threads race to intern the same value; whichever loses the race must drop its
+    // own allocation and hand back the winner's `Id` instead of minting a second one.
+    let ids: Vec<_> = (0..8)
+        .map(|_| thread::spawn(|| Id::try_new(Leaf(2)).unwrap()))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+
+    for id in &ids[1..] {
+        assert_eq!(ids[0], *id);
+        assert_eq!(ids[0].as_ref() as *const Fallible, id.as_ref() as *const Fallible);
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(Clone, Hash, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+enum Tree {
+    Leaf(i32),
+    Node(Id<Tree>, Id<Tree>),
+}
+#[cfg(feature = "serde")]
+make_allocator!(Tree, __TREE_ALLOC, HashAllocator);
+
+#[cfg(feature = "serde")]
+#[test]
+fn deserializing_equal_values_separately_still_shares_a_pointer() {
+    let original = Id::new(Tree::Node(Id::new(Tree::Leaf(1)), Id::new(Tree::Leaf(2))));
+    let json = serde_json::to_string(&original).unwrap();
+
+    let a: Id<Tree> = serde_json::from_str(&json).unwrap();
+    let b: Id<Tree> = serde_json::from_str(&json).unwrap();
+    assert_eq!(a, original);
+    assert_eq!(a.as_ref() as *const Tree, b.as_ref() as *const Tree);
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug)]
+enum Sweepable {
+    Leaf(i32),
+}
+make_allocator!(Sweepable, __SWEEPABLE_ALLOC, HashAllocator);
+
+#[test]
+fn surviving_values_stay_findable_after_a_sweep_resizes_the_table() {
+    use Sweepable::Leaf;
+
+    // Enough distinct values that delete_unused below forces at least one shard to actually
+    // shrink (and therefore rehash), not just scan an already-empty table.
+    let kept: Vec<_> = (0..64).map(Leaf).map(Id::new).collect();
+    let transient: Vec<_> = (1000..1064).map(Leaf).map(Id::new).collect();
+    drop(transient);
+
+    Sweepable::allocator().delete_unused();
+
+    for (n, id) in kept.iter().enumerate() {
+        let again = Id::new(Leaf(n as i32));
+        assert_eq!(again, *id, "re-allocating a surviving value after a sweep should dedup");
+        assert_eq!(
+            again.as_ref() as *const Sweepable,
+            id.as_ref() as *const Sweepable,
+            "re-allocating a surviving value after a sweep must return the same pointer"
+        );
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[derive(Clone, Hash, PartialEq, Eq, Debug)]
+enum Parallel {
+    Leaf(i32),
+}
+#[cfg(feature = "rayon")]
+make_allocator!(Parallel, __PARALLEL_ALLOC, HashAllocator);
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_allocate_many_collapses_duplicates_like_sequential_allocation() {
+    use Parallel::Leaf;
+
+    let inputs: Vec<_> = (0..64).map(|n| Leaf(n % 8)).collect();
+    let sequential: Vec<_> = inputs.iter().cloned().map(Id::new).collect();
+
+    let parallel = Parallel::allocator().par_allocate_many(inputs);
+
+    assert_eq!(parallel, sequential);
+    assert_eq!(Parallel::allocator().allocations(), 8);
+}
+
+/// A trivial storage allocator that just delegates to `Global`, to prove `HashAllocator` works
+/// with a storage allocator other than the default.
+#[derive(Clone, Copy, Default)]
+struct BumpLikeAllocator;
+
+unsafe impl std::alloc::Allocator for BumpLikeAllocator {
+    fn allocate(
+        &self,
+        layout: std::alloc::Layout,
+    ) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
+        std::alloc::Global.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: std::alloc::Layout) {
+        unsafe { std::alloc::Global.deallocate(ptr, layout) }
+    }
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, Debug)]
+enum Bumped {
+    Leaf(i32),
+}
+make_allocator!(Bumped, __BUMPED_ALLOC, HashAllocator, BumpLikeAllocator);
+
+#[test]
+fn custom_storage_allocator_still_dedups_and_sweeps() {
+    use Bumped::Leaf;
+
+    let a = Id::new(Leaf(1));
+    let b = Id::new(Leaf(1));
+    assert_eq!(a.as_ref() as *const Bumped, b.as_ref() as *const Bumped);
+
+    drop(a);
+    drop(b);
+    Bumped::allocator().delete_unused();
+    assert_eq!(Bumped::allocator().allocations(), 0);
+}